@@ -1,5 +1,6 @@
 use clap::{Parser, Subcommand};
-use std::io::Write;
+use serde::Deserialize;
+use std::io::{Read, Seek, Write};
 use std::path::{Path, PathBuf};
 use std::process::{self, Command};
 
@@ -21,12 +22,74 @@ enum Cmd {
     },
     /// Build and run the kernel in QEMU
     Run {
-        /// Target architecture: riscv64, aarch64, x86_64, loongarch64
-        #[arg(long, default_value = "riscv64")]
-        arch: String,
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Build and run the kernel in QEMU, halted at reset with a GDB stub and
+    /// interrupt/exception and guest-error tracing enabled
+    Debug {
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// List or extract files from the built disk image without booting QEMU
+    Inspect {
+        /// Disk image filesystem format; only consulted when the image has
+        /// no sidecar marker (e.g. built before this check existed) since
+        /// the marker written by `run`/`debug` is otherwise authoritative
+        #[arg(long, value_enum, default_value_t = DiskFormat::Fat32)]
+        fs: DiskFormat,
+        /// The image was built with --partitioned (filesystem lives in
+        /// partition 1 behind an MBR, not at offset 0); same fallback-only
+        /// behavior as --fs when a sidecar marker is present
+        #[arg(long)]
+        partitioned: bool,
+        /// List only this subdirectory instead of walking the whole tree
+        #[arg(long)]
+        ls: Option<String>,
+        /// Pull a single file out of the image: --extract <disk-path> <host-path>
+        #[arg(long, num_args = 2, value_names = ["DISK_PATH", "HOST_PATH"])]
+        extract: Option<Vec<String>>,
     },
 }
 
+/// Options shared by `Run` and `Debug`: which kernel/disk image to build and
+/// how to launch QEMU with it.
+#[derive(clap::Args)]
+struct RunArgs {
+    /// Target architecture: riscv64, aarch64, x86_64, loongarch64
+    #[arg(long, default_value = "riscv64")]
+    arch: String,
+    /// Rebuild the disk image even if it looks up to date
+    #[arg(long)]
+    force_disk: bool,
+    /// Disk image filesystem format
+    #[arg(long, value_enum, default_value_t = DiskFormat::Fat32)]
+    fs: DiskFormat,
+    /// Emit an MBR partition table and put the filesystem in partition 1
+    /// instead of formatting the whole image
+    #[arg(long)]
+    partitioned: bool,
+    /// Memory allocated to the guest (QEMU `-m` argument)
+    #[arg(long, default_value = "128M")]
+    mem: String,
+    /// Number of guest vCPUs (QEMU `-smp` argument)
+    #[arg(long, default_value = "1")]
+    smp: String,
+    /// Redirect guest serial console output to a file instead of stdio
+    #[arg(long)]
+    serial: Option<PathBuf>,
+    /// Extra argument passed verbatim to qemu-system-<arch> (repeatable)
+    #[arg(long = "qemu-arg")]
+    qemu_arg: Vec<String>,
+}
+
+/// On-disk filesystem format used for the image attached to the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum DiskFormat {
+    Fat32,
+    Ext2,
+}
+
 #[allow(dead_code)]
 struct ArchInfo {
     target: &'static str,
@@ -92,13 +155,350 @@ fn install_config(root: &Path, arch: &str) {
     println!("Installed config: {} -> .axconfig.toml", src.display());
 }
 
-/// Create a 64MB FAT32 disk image containing `/sbin/origin.bin`.
+/// A single staged file entry from `system.toml`: `source` is a path on the
+/// host, `dest` is the absolute path it should be written to inside the
+/// disk image (e.g. `/sbin/app.bin`).
+#[derive(Deserialize)]
+struct ManifestFile {
+    source: PathBuf,
+    dest: String,
+}
+
+/// A directory to precreate inside the disk image, e.g. `/sbin` or `/etc/app`.
+#[derive(Deserialize)]
+struct ManifestDir {
+    path: String,
+}
+
+/// Describes the contents of the disk image built by `create_fat_disk_image`.
 ///
-/// The image is formatted as FAT32 using the `fatfs` crate.
-/// A sample binary file is placed at `/sbin/origin.bin` so that
-/// the application can read it via the ArceOS filesystem layer.
-fn create_fat_disk_image(path: &Path) {
-    const DISK_SIZE: u64 = 64 * 1024 * 1024; // 64MB
+/// Read from a `system.toml` manifest at the project root. When absent, the
+/// built-in single-file `/sbin/origin.bin` layout is used instead.
+#[derive(Deserialize, Default)]
+struct DiskManifest {
+    size_mb: Option<u64>,
+    fat_type: Option<String>,
+    #[serde(rename = "files", default)]
+    files: Vec<ManifestFile>,
+    #[serde(rename = "dirs", default)]
+    dirs: Vec<ManifestDir>,
+}
+
+/// Load and parse `system.toml` from the project root, if it exists.
+fn load_disk_manifest(root: &Path) -> Option<DiskManifest> {
+    let path = root.join("system.toml");
+    if !path.exists() {
+        return None;
+    }
+    let text = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+        eprintln!("Error: failed to read {}: {}", path.display(), e);
+        process::exit(1);
+    });
+    let manifest: DiskManifest = toml::from_str(&text).unwrap_or_else(|e| {
+        eprintln!("Error: failed to parse {}: {}", path.display(), e);
+        process::exit(1);
+    });
+    Some(manifest)
+}
+
+fn fat_type_from_name(name: &str) -> fatfs::FatType {
+    match name {
+        "fat12" => fatfs::FatType::Fat12,
+        "fat16" => fatfs::FatType::Fat16,
+        "fat32" => fatfs::FatType::Fat32,
+        _ => {
+            eprintln!(
+                "Error: unsupported fat_type '{}' in system.toml. Supported: fat12, fat16, fat32",
+                name
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// Create each missing directory component of `dest_dir` (a `/`-separated
+/// absolute path) inside `root_dir`, so that nested destinations such as
+/// `/etc/app/config` don't need to be precreated by hand.
+fn create_dir_recursive<IO: fatfs::ReadWriteSeek, TP, OCC>(
+    root_dir: &fatfs::Dir<IO, TP, OCC>,
+    dest_dir: &str,
+) where
+    TP: fatfs::TimeProvider,
+    OCC: fatfs::OemCpConverter,
+{
+    let mut current = String::new();
+    for component in dest_dir.split('/').filter(|c| !c.is_empty()) {
+        if !current.is_empty() {
+            current.push('/');
+        }
+        current.push_str(component);
+        match root_dir.create_dir(&current) {
+            Ok(_) => {}
+            Err(fatfs::Error::AlreadyExists) => {}
+            Err(e) => {
+                eprintln!("Error: failed to create directory /{}: {}", current, e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Write `content` to `dest` (an absolute path) inside `root_dir`, creating
+/// any missing parent directories first.
+fn write_fat_file<IO: fatfs::ReadWriteSeek, TP, OCC>(
+    root_dir: &fatfs::Dir<IO, TP, OCC>,
+    dest: &str,
+    content: &[u8],
+) where
+    TP: fatfs::TimeProvider,
+    OCC: fatfs::OemCpConverter,
+{
+    let dest = dest.trim_start_matches('/');
+    if let Some((parent, _)) = dest.rsplit_once('/') {
+        create_dir_recursive(root_dir, parent);
+    }
+    let mut f = root_dir.create_file(dest).unwrap_or_else(|e| {
+        eprintln!("Error: failed to create /{}: {}", dest, e);
+        process::exit(1);
+    });
+    f.write_all(content).unwrap();
+    f.flush().unwrap();
+}
+
+/// Resolve a manifest `source` path against `root`, so a relative path in
+/// `system.toml` is always found regardless of the directory `cargo xtask`
+/// happens to be invoked from. An absolute `source` is returned unchanged.
+fn manifest_source_path(root: &Path, source: &Path) -> PathBuf {
+    root.join(source)
+}
+
+/// The host paths that feed the disk image: `system.toml` itself plus every
+/// `source` file it references. Used to decide whether a rebuild is needed.
+fn disk_image_inputs(root: &Path) -> Vec<PathBuf> {
+    let manifest_path = root.join("system.toml");
+    let mut inputs = Vec::new();
+    if let Some(manifest) = load_disk_manifest(root) {
+        inputs.push(manifest_path);
+        for entry in manifest.files {
+            inputs.push(manifest_source_path(root, &entry.source));
+        }
+    }
+    inputs
+}
+
+/// Name used to record `fmt` in the sidecar marker file.
+fn disk_format_name(fmt: DiskFormat) -> &'static str {
+    match fmt {
+        DiskFormat::Fat32 => "fat32",
+        DiskFormat::Ext2 => "ext2",
+    }
+}
+
+/// Inverse of `disk_format_name`, for reading the sidecar marker back.
+fn disk_format_from_name(name: &str) -> Option<DiskFormat> {
+    match name {
+        "fat32" => Some(DiskFormat::Fat32),
+        "ext2" => Some(DiskFormat::Ext2),
+        _ => None,
+    }
+}
+
+/// Path of the small sidecar file recording the format/partition layout
+/// `disk` was last built with, so `disk_image_up_to_date` can tell a stale
+/// image (right mtimes, wrong `--fs`/`--partitioned`) from a fresh one.
+fn disk_image_marker_path(disk: &Path) -> PathBuf {
+    let mut name = disk.as_os_str().to_owned();
+    name.push(".meta");
+    PathBuf::from(name)
+}
+
+/// Record the format and partition layout `disk` was just built with.
+fn write_disk_image_marker(disk: &Path, fmt: DiskFormat, partitioned: bool) {
+    let contents = format!("{}\n{}\n", disk_format_name(fmt), partitioned);
+    std::fs::write(disk_image_marker_path(disk), contents).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write disk image marker: {}", e);
+        process::exit(1);
+    });
+}
+
+/// Read back the format/partition layout recorded by
+/// `write_disk_image_marker`, or `None` if no marker is present (e.g. an
+/// image built before this check existed) or it's malformed.
+fn read_disk_image_marker(disk: &Path) -> Option<(String, bool)> {
+    let text = std::fs::read_to_string(disk_image_marker_path(disk)).ok()?;
+    let mut lines = text.lines();
+    let fmt = lines.next()?.to_string();
+    let partitioned = lines.next()?.parse().ok()?;
+    Some((fmt, partitioned))
+}
+
+/// Check whether `disk` already reflects the current manifest/source files
+/// and was built with the requested format/partition layout, so a rebuild
+/// can be skipped. Returns `false` if `disk` is missing, if any input is
+/// missing or newer than `disk`, or if `disk` was built with a different
+/// `--fs`/`--partitioned`.
+fn disk_image_up_to_date(disk: &Path, root: &Path, fmt: DiskFormat, partitioned: bool) -> bool {
+    match read_disk_image_marker(disk) {
+        Some((recorded_fmt, recorded_partitioned))
+            if recorded_fmt == disk_format_name(fmt) && recorded_partitioned == partitioned => {}
+        _ => return false,
+    }
+
+    let disk_mtime = match std::fs::metadata(disk).and_then(|m| m.modified()) {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+    for input in disk_image_inputs(root) {
+        match std::fs::metadata(&input).and_then(|m| m.modified()) {
+            Ok(t) if t <= disk_mtime => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Sectors reserved for the MBR and its padding before the first partition,
+/// matching the classic "partition 1 starts at LBA 2048" convention.
+const MBR_RESERVED_SECTORS: u64 = 2048;
+const SECTOR_SIZE: u64 = 512;
+
+/// Work out where the filesystem region starts and how long it is within a
+/// `disk_size`-byte image. Unpartitioned images use the whole file; a
+/// `partitioned` image reserves `MBR_RESERVED_SECTORS` up front for the MBR,
+/// so `disk_size` must be large enough to leave a non-empty filesystem
+/// region afterwards.
+fn partition_layout(disk_size: u64, partitioned: bool) -> Result<(u64, u64), String> {
+    let partition_offset = if partitioned { MBR_RESERVED_SECTORS * SECTOR_SIZE } else { 0 };
+    if disk_size <= partition_offset {
+        return Err(format!(
+            "disk size ({disk_size} bytes) is too small for --partitioned: the MBR reserves the \
+             first {partition_offset} bytes, leaving no room for a filesystem. Increase size_mb \
+             in system.toml."
+        ));
+    }
+    Ok((partition_offset, disk_size - partition_offset))
+}
+
+/// Wraps a file handle so all reads/writes/seeks are offset by a fixed
+/// number of bytes and bounded to a fixed length, letting `fatfs` operate
+/// directly on a partition region instead of starting at byte 0.
+struct OffsetIo<'a> {
+    file: &'a std::fs::File,
+    offset: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<'a> OffsetIo<'a> {
+    fn new(file: &'a std::fs::File, offset: u64, len: u64) -> Self {
+        Self { file, offset, len, pos: 0 }
+    }
+}
+
+impl<'a> std::io::Read for OffsetIo<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        (&*self.file).seek(std::io::SeekFrom::Start(self.offset + self.pos))?;
+        let max = self.len.saturating_sub(self.pos).min(buf.len() as u64) as usize;
+        let n = (&*self.file).read(&mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a> std::io::Write for OffsetIo<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        (&*self.file).seek(std::io::SeekFrom::Start(self.offset + self.pos))?;
+        let max = self.len.saturating_sub(self.pos).min(buf.len() as u64) as usize;
+        let n = (&*self.file).write(&buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        (&*self.file).flush()
+    }
+}
+
+impl<'a> std::io::Seek for OffsetIo<'a> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let new_pos: i64 = match pos {
+            std::io::SeekFrom::Start(p) => p as i64,
+            std::io::SeekFrom::Current(d) => self.pos as i64 + d,
+            std::io::SeekFrom::End(d) => self.len as i64 + d,
+        };
+        if new_pos < 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before start of partition region",
+            ));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+/// Map a `fatfs::FatType` to the MBR partition type byte that describes it:
+/// `0x01` for FAT12, `0x06` for FAT16, `0x0C` for FAT32 (LBA).
+fn mbr_partition_type(fat_type: fatfs::FatType) -> u8 {
+    match fat_type {
+        fatfs::FatType::Fat12 => 0x01,
+        fatfs::FatType::Fat16 => 0x06,
+        fatfs::FatType::Fat32 => 0x0C,
+    }
+}
+
+/// Write a single-entry MBR partition table to the start of `file`, giving
+/// partition 1 the type byte matching `fat_type`, the requested start LBA
+/// and sector count, and the bootable flag set. `fatfs` must then be
+/// pointed at `start_lba * 512` rather than offset 0 so the guest finds
+/// the same filesystem the partition table describes.
+fn write_mbr(file: &std::fs::File, start_lba: u32, sector_count: u32, fat_type: fatfs::FatType) {
+    let mut mbr = mbrs::Mbr::default();
+    mbr.partition_table[0] = mbrs::PartitionTableEntry {
+        boot_indicator: 0x80,
+        partition_type: mbr_partition_type(fat_type),
+        start_lba,
+        size_in_lba: sector_count,
+        ..Default::default()
+    };
+
+    (&*file)
+        .seek(std::io::SeekFrom::Start(0))
+        .unwrap_or_else(|e| {
+            eprintln!("Error: failed to seek to MBR: {}", e);
+            process::exit(1);
+        });
+    (&*file).write_all(&mbr.to_bytes()).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write MBR: {}", e);
+        process::exit(1);
+    });
+}
+
+/// Create a FAT disk image at `path`, populated either from a `system.toml`
+/// manifest at the project root, or from the built-in single-file layout.
+///
+/// With a manifest, every `[[dirs]]` entry is precreated and every
+/// `[[files]]` entry is copied in from its host `source` path to its `dest`
+/// path, creating parent directories as needed. Without one, the image
+/// falls back to the original `/sbin/origin.bin` with synthetic bytes.
+///
+/// When `partitioned` is set, the first `MBR_RESERVED_SECTORS` are reserved
+/// for an MBR partition table (see `write_mbr`) and the FAT filesystem is
+/// formatted inside that partition instead of at offset 0, so the guest
+/// must parse the partition table to find it.
+fn create_fat_disk_image(path: &Path, root: &Path, partitioned: bool) {
+    const DEFAULT_DISK_SIZE_MB: u64 = 64;
+
+    let manifest = load_disk_manifest(root);
+    let disk_size_mb = manifest
+        .as_ref()
+        .and_then(|m| m.size_mb)
+        .unwrap_or(DEFAULT_DISK_SIZE_MB);
+    let disk_size = disk_size_mb * 1024 * 1024;
+    let fat_type = manifest
+        .as_ref()
+        .and_then(|m| m.fat_type.as_deref())
+        .map(fat_type_from_name)
+        .unwrap_or(fatfs::FatType::Fat32);
 
     // Create or truncate the image file at the requested size.
     let file = std::fs::OpenOptions::new()
@@ -111,38 +511,144 @@ fn create_fat_disk_image(path: &Path) {
             eprintln!("Error: failed to create disk image {}: {}", path.display(), e);
             process::exit(1);
         });
-    file.set_len(DISK_SIZE).unwrap();
+    file.set_len(disk_size).unwrap();
+
+    let (partition_offset, fs_len) = partition_layout(disk_size, partitioned).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    });
 
-    // Format the image as FAT32.
-    let format_opts = fatfs::FormatVolumeOptions::new().fat_type(fatfs::FatType::Fat32);
-    fatfs::format_volume(&file, format_opts).unwrap_or_else(|e| {
-        eprintln!("Error: failed to format FAT32: {}", e);
+    if partitioned {
+        let sector_count = (fs_len / SECTOR_SIZE) as u32;
+        write_mbr(&file, MBR_RESERVED_SECTORS as u32, sector_count, fat_type);
+    }
+
+    let mut io = OffsetIo::new(&file, partition_offset, fs_len);
+
+    // Format the image.
+    let format_opts = fatfs::FormatVolumeOptions::new().fat_type(fat_type);
+    fatfs::format_volume(&mut io, format_opts).unwrap_or_else(|e| {
+        eprintln!("Error: failed to format FAT filesystem: {}", e);
         process::exit(1);
     });
 
     // Open the filesystem and populate it.
     {
-        let fs = fatfs::FileSystem::new(&file, fatfs::FsOptions::new()).unwrap_or_else(|e| {
+        let fs = fatfs::FileSystem::new(&mut io, fatfs::FsOptions::new()).unwrap_or_else(|e| {
             eprintln!("Error: failed to open FAT filesystem: {}", e);
             process::exit(1);
         });
 
         let root_dir = fs.root_dir();
 
-        // Create /sbin directory
+        match &manifest {
+            Some(manifest) => {
+                for dir in &manifest.dirs {
+                    create_dir_recursive(&root_dir, &dir.path);
+                }
+                for entry in &manifest.files {
+                    let source = manifest_source_path(root, &entry.source);
+                    let content = std::fs::read(&source).unwrap_or_else(|e| {
+                        eprintln!(
+                            "Error: failed to read source file {}: {}",
+                            source.display(),
+                            e
+                        );
+                        process::exit(1);
+                    });
+                    write_fat_file(&root_dir, &entry.dest, &content);
+                }
+                println!(
+                    "Created FAT disk image: {} ({}MB) from system.toml ({} file(s), {} dir(s))",
+                    path.display(),
+                    disk_size_mb,
+                    manifest.files.len(),
+                    manifest.dirs.len()
+                );
+            }
+            None => {
+                // Create /sbin directory
+                root_dir.create_dir("sbin").unwrap_or_else(|e| {
+                    eprintln!("Error: failed to create /sbin directory: {}", e);
+                    process::exit(1);
+                });
+
+                // Create /sbin/origin.bin with sample binary content.
+                // The application reads the first 64 bytes and prints the first 8 as hex.
+                let mut f = root_dir.create_file("sbin/origin.bin").unwrap_or_else(|e| {
+                    eprintln!("Error: failed to create /sbin/origin.bin: {}", e);
+                    process::exit(1);
+                });
+
+                // Write 64 bytes of recognizable binary data.
+                let mut content = [0u8; 64];
+                for (i, byte) in content.iter_mut().enumerate() {
+                    *byte = (i as u8).wrapping_mul(0x11).wrapping_add(0x10);
+                }
+                f.write_all(&content).unwrap();
+                f.flush().unwrap();
+
+                println!(
+                    "Created FAT disk image: {} ({}MB) with /sbin/origin.bin",
+                    path.display(),
+                    disk_size_mb
+                );
+            }
+        }
+    } // fs and root_dir dropped here, flushing all metadata
+}
+
+/// Create a 64MB ext2 disk image containing `/sbin/origin.bin`.
+///
+/// Mirrors the built-in layout of `create_fat_disk_image`, but formats the
+/// image as ext2 via the `ext2` crate so the guest exercises the kernel's
+/// ext2 driver instead of only FAT. The manifest-driven layout from
+/// `system.toml` is currently FAT-only; ext2 always gets the built-in
+/// single-file layout.
+fn create_ext2_disk_image(path: &Path) {
+    const DISK_SIZE: u64 = 64 * 1024 * 1024; // 64MB
+
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .unwrap_or_else(|e| {
+            eprintln!("Error: failed to create disk image {}: {}", path.display(), e);
+            process::exit(1);
+        });
+    file.set_len(DISK_SIZE).unwrap();
+
+    // Format the image as ext2.
+    let format_opts = ext2::FormatVolumeOptions::new();
+    ext2::format_volume(&file, format_opts).unwrap_or_else(|e| {
+        eprintln!("Error: failed to format ext2: {}", e);
+        process::exit(1);
+    });
+
+    // Open the filesystem and populate it.
+    {
+        let fs = ext2::FileSystem::new(&file, ext2::FsOptions::new()).unwrap_or_else(|e| {
+            eprintln!("Error: failed to open ext2 filesystem: {}", e);
+            process::exit(1);
+        });
+
+        let root_dir = fs.root_dir();
+
+        // Create the /sbin directory inode.
         root_dir.create_dir("sbin").unwrap_or_else(|e| {
             eprintln!("Error: failed to create /sbin directory: {}", e);
             process::exit(1);
         });
 
-        // Create /sbin/origin.bin with sample binary content.
-        // The application reads the first 64 bytes and prints the first 8 as hex.
+        // Create /sbin/origin.bin with the same sample binary content as
+        // the FAT layout, so the application's read path is unaffected.
         let mut f = root_dir.create_file("sbin/origin.bin").unwrap_or_else(|e| {
             eprintln!("Error: failed to create /sbin/origin.bin: {}", e);
             process::exit(1);
         });
 
-        // Write 64 bytes of recognizable binary data.
         let mut content = [0u8; 64];
         for (i, byte) in content.iter_mut().enumerate() {
             *byte = (i as u8).wrapping_mul(0x11).wrapping_add(0x10);
@@ -152,12 +658,216 @@ fn create_fat_disk_image(path: &Path) {
     } // fs and root_dir dropped here, flushing all metadata
 
     println!(
-        "Created FAT32 disk image: {} ({}MB) with /sbin/origin.bin",
+        "Created ext2 disk image: {} ({}MB) with /sbin/origin.bin",
         path.display(),
         DISK_SIZE / (1024 * 1024)
     );
 }
 
+/// Create the disk image at `path` in the requested format, dispatching to
+/// the FAT or ext2 backend. `partitioned` requests an MBR partition table
+/// with the filesystem inside partition 1; only the FAT backend supports it.
+fn create_disk_image(path: &Path, root: &Path, fmt: DiskFormat, partitioned: bool) {
+    match fmt {
+        DiskFormat::Fat32 => create_fat_disk_image(path, root, partitioned),
+        DiskFormat::Ext2 => {
+            if partitioned {
+                eprintln!("Error: --partitioned is only supported with --fs fat32");
+                process::exit(1);
+            }
+            if load_disk_manifest(root).is_some() {
+                eprintln!(
+                    "Warning: system.toml manifest is ignored with --fs ext2; only the \
+                     built-in /sbin/origin.bin is written"
+                );
+            }
+            create_ext2_disk_image(path)
+        }
+    }
+}
+
+/// Open `target/disk.img` read-only, erroring out with a message naming the
+/// actual problem (missing image, or a format `inspect` can't read) rather
+/// than a raw `fatfs` parse error.
+fn open_disk_image_file(root: &Path, fmt: DiskFormat) -> std::fs::File {
+    if fmt == DiskFormat::Ext2 {
+        eprintln!(
+            "Error: `inspect` does not support ext2 images yet (--fs ext2); only FAT images \
+             built with --fs fat32 can be inspected."
+        );
+        process::exit(1);
+    }
+    let disk = root.join("target").join("disk.img");
+    std::fs::OpenOptions::new()
+        .read(true)
+        .open(&disk)
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "Error: failed to open disk image {}: {} (run `xtask run` first)",
+                disk.display(),
+                e
+            );
+            process::exit(1);
+        })
+}
+
+/// Print a `ls -l`-ish line for a single file: its absolute path, size, and
+/// a short hex preview of its first bytes.
+fn print_file_entry<IO: fatfs::ReadWriteSeek, TP, OCC>(
+    path: &str,
+    entry: &fatfs::DirEntry<IO, TP, OCC>,
+) where
+    TP: fatfs::TimeProvider,
+    OCC: fatfs::OemCpConverter,
+{
+    const PREVIEW_LEN: usize = 16;
+
+    let size = entry.len();
+    let mut preview = vec![0u8; (size as usize).min(PREVIEW_LEN)];
+    let mut file = entry.to_file();
+    let n = file.read(&mut preview).unwrap_or(0);
+    let hex: String = preview[..n].iter().map(|b| format!("{:02x}", b)).collect();
+
+    println!("{:<40} {:>10}  {}", path, size, hex);
+}
+
+/// Recursively walk `dir` (rooted at absolute path `prefix`), printing every
+/// file and descending into every subdirectory.
+fn walk_and_print<IO: fatfs::ReadWriteSeek, TP, OCC>(dir: &fatfs::Dir<IO, TP, OCC>, prefix: &str)
+where
+    TP: fatfs::TimeProvider,
+    OCC: fatfs::OemCpConverter,
+{
+    for entry in dir.iter() {
+        let entry = entry.unwrap_or_else(|e| {
+            eprintln!("Error: failed to read directory entry under {}: {}", prefix, e);
+            process::exit(1);
+        });
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        let path = format!("{}/{}", prefix, name);
+        if entry.is_dir() {
+            walk_and_print(&entry.to_dir(), &path);
+        } else {
+            print_file_entry(&path, &entry);
+        }
+    }
+}
+
+/// Resolve an absolute `/`-separated path inside `root_dir` to its `Dir`,
+/// descending one component at a time.
+fn resolve_dir<IO: fatfs::ReadWriteSeek, TP, OCC>(
+    root_dir: &fatfs::Dir<IO, TP, OCC>,
+    path: &str,
+) -> fatfs::Dir<IO, TP, OCC>
+where
+    TP: fatfs::TimeProvider,
+    OCC: fatfs::OemCpConverter,
+{
+    let mut dir = root_dir.clone();
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        dir = dir.open_dir(component).unwrap_or_else(|e| {
+            eprintln!("Error: failed to open directory /{}: {}", path.trim_start_matches('/'), e);
+            process::exit(1);
+        });
+    }
+    dir
+}
+
+/// List or extract files from an already-opened FAT filesystem.
+///
+/// With no options, walks the whole tree and prints each file's path, size,
+/// and a short hex preview. `--ls <dir>` restricts the walk to one
+/// subdirectory; `--extract <disk-path> <host-path>` copies a single file
+/// out to the host instead of listing anything.
+fn run_inspect<IO: fatfs::ReadWriteSeek, TP, OCC>(
+    root_dir: &fatfs::Dir<IO, TP, OCC>,
+    ls: Option<&str>,
+    extract: Option<&[String]>,
+) where
+    TP: fatfs::TimeProvider,
+    OCC: fatfs::OemCpConverter,
+{
+    if let Some(args) = extract {
+        let (disk_path, host_path) = (&args[0], &args[1]);
+        let disk_path = disk_path.trim_start_matches('/');
+        let (dir_path, file_name) = disk_path.rsplit_once('/').unwrap_or(("", disk_path));
+        let dir = resolve_dir(root_dir, dir_path);
+        let mut file = dir.open_file(file_name).unwrap_or_else(|e| {
+            eprintln!("Error: failed to open /{}: {}", disk_path, e);
+            process::exit(1);
+        });
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap_or_else(|e| {
+            eprintln!("Error: failed to read /{}: {}", disk_path, e);
+            process::exit(1);
+        });
+        std::fs::write(host_path, &content).unwrap_or_else(|e| {
+            eprintln!("Error: failed to write {}: {}", host_path, e);
+            process::exit(1);
+        });
+        println!("Extracted /{} -> {} ({} bytes)", disk_path, host_path, content.len());
+        return;
+    }
+
+    match ls {
+        Some(path) => {
+            let dir = resolve_dir(root_dir, path);
+            let prefix = format!("/{}", path.trim_start_matches('/').trim_end_matches('/'));
+            walk_and_print(&dir, prefix.trim_end_matches('/'));
+        }
+        None => walk_and_print(root_dir, ""),
+    }
+}
+
+/// List or extract files from `target/disk.img` without booting QEMU.
+///
+/// The disk image's sidecar marker (written by `run`/`debug` via
+/// `write_disk_image_marker`) records the format/partition layout it was
+/// actually built with; when present it takes precedence over `fs`/
+/// `partitioned` so `inspect` can't drift out of sync the way `run`/`debug`
+/// used to before that marker existed. The flags are only consulted as a
+/// fallback for images built before the marker existed.
+fn do_inspect(root: &Path, fs: DiskFormat, partitioned: bool, ls: Option<&str>, extract: Option<&[String]>) {
+    let disk = root.join("target").join("disk.img");
+    let (fmt, partitioned) = match read_disk_image_marker(&disk) {
+        Some((recorded_fmt, recorded_partitioned)) => (
+            disk_format_from_name(&recorded_fmt).unwrap_or(fs),
+            recorded_partitioned,
+        ),
+        None => (fs, partitioned),
+    };
+    let file = open_disk_image_file(root, fmt);
+
+    if partitioned {
+        let file_len = file
+            .metadata()
+            .unwrap_or_else(|e| {
+                eprintln!("Error: failed to stat disk image: {}", e);
+                process::exit(1);
+            })
+            .len();
+        let (partition_offset, fs_len) = partition_layout(file_len, true).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        });
+        let mut io = OffsetIo::new(&file, partition_offset, fs_len);
+        let fs = fatfs::FileSystem::new(&mut io, fatfs::FsOptions::new()).unwrap_or_else(|e| {
+            eprintln!("Error: failed to open FAT filesystem in partitioned disk image: {}", e);
+            process::exit(1);
+        });
+        run_inspect(&fs.root_dir(), ls, extract);
+    } else {
+        let fs = fatfs::FileSystem::new(file, fatfs::FsOptions::new()).unwrap_or_else(|e| {
+            eprintln!("Error: failed to open FAT filesystem: {}", e);
+            process::exit(1);
+        });
+        run_inspect(&fs.root_dir(), ls, extract);
+    }
+}
+
 /// Run cargo build for the target architecture.
 fn do_build(root: &Path, info: &ArchInfo) {
     let manifest = root.join("Cargo.toml");
@@ -200,17 +910,14 @@ fn do_objcopy(elf: &Path, bin: &Path, objcopy_arch: &str) {
 }
 
 /// Run the kernel image in QEMU with a VirtIO block device.
-fn do_run_qemu(arch: &str, elf: &Path, bin: &Path, disk: &Path) {
-    let mem = "128M";
-    let smp = "1";
-
+fn do_run_qemu(arch: &str, elf: &Path, bin: &Path, disk: &Path, debug: bool, run: &RunArgs) {
     let qemu = format!("qemu-system-{arch}");
 
     let mut args: Vec<String> = vec![
         "-m".into(),
-        mem.into(),
+        run.mem.clone(),
         "-smp".into(),
-        smp.into(),
+        run.smp.clone(),
         "-nographic".into(),
     ];
 
@@ -262,6 +969,34 @@ fn do_run_qemu(arch: &str, elf: &Path, bin: &Path, disk: &Path) {
         "virtio-blk-pci,drive=disk0".into(),
     ]);
 
+    // Redirect the guest serial console to a file instead of stdio, e.g. for
+    // CI that needs to capture and assert on console output.
+    if let Some(serial) = &run.serial {
+        args.extend(["-serial".into(), format!("file:{}", serial.display())]);
+    }
+
+    args.extend(run.qemu_arg.iter().cloned());
+
+    if debug {
+        let trace_log = disk.with_file_name("qemu-trace.log");
+        args.extend([
+            "-s".into(),
+            "-S".into(),
+            "-D".into(),
+            trace_log.to_str().unwrap().into(),
+            "-d".into(),
+            "int,guest_errors".into(),
+        ]);
+        println!(
+            "GDB stub listening on tcp::1234, halted at reset. Trace log: {}",
+            trace_log.display()
+        );
+        println!(
+            "Connect with: gdb-multiarch -ex 'target remote :1234' {}",
+            elf.display()
+        );
+    }
+
     println!("Running: {} {}", qemu, args.join(" "));
     let status = Command::new(&qemu)
         .args(&args)
@@ -287,28 +1022,196 @@ fn main() {
             do_build(&root, &info);
             println!("Build complete for {arch} ({})", info.target);
         }
-        Cmd::Run { ref arch } => {
-            let info = arch_info(arch);
-            install_config(&root, arch);
-            do_build(&root, &info);
+        Cmd::Run { run } => prepare_and_run(&root, &run, false),
+        Cmd::Debug { run } => prepare_and_run(&root, &run, true),
+        Cmd::Inspect { fs, partitioned, ref ls, ref extract } => {
+            do_inspect(&root, fs, partitioned, ls.as_deref(), extract.as_deref());
+        }
+    }
+}
 
-            let elf = root
-                .join("target")
-                .join(info.target)
-                .join("release")
-                .join("arceos-loadapp");
-            let bin = elf.with_extension("bin");
+/// Shared body of `Run` and `Debug`: install the target config, build the
+/// kernel, (re)build the disk image if stale, objcopy for non-x86_64
+/// targets, then hand off to QEMU with `debug` selecting the GDB-stub /
+/// trace-logging mode.
+fn prepare_and_run(root: &Path, run: &RunArgs, debug: bool) {
+    let info = arch_info(&run.arch);
+    install_config(root, &run.arch);
+    do_build(root, &info);
 
-            // Create FAT32 disk image with /sbin/origin.bin
-            let disk = root.join("target").join("disk.img");
-            create_fat_disk_image(&disk);
+    let elf = root
+        .join("target")
+        .join(info.target)
+        .join("release")
+        .join("arceos-loadapp");
+    let bin = elf.with_extension("bin");
 
-            // objcopy for non-x86_64 architectures
-            if arch != "x86_64" {
-                do_objcopy(&elf, &bin, info.objcopy_arch);
-            }
+    // Create the disk image, staging files from system.toml if present.
+    // Skip the rebuild when it's already newer than everything that feeds it
+    // and was built with the same --fs and --partitioned.
+    let disk = root.join("target").join("disk.img");
+    if run.force_disk || !disk_image_up_to_date(&disk, root, run.fs, run.partitioned) {
+        create_disk_image(&disk, root, run.fs, run.partitioned);
+        write_disk_image_marker(&disk, run.fs, run.partitioned);
+    } else {
+        println!("disk image up to date");
+    }
+
+    // objcopy for non-x86_64 architectures
+    if run.arch != "x86_64" {
+        do_objcopy(&elf, &bin, info.objcopy_arch);
+    }
+
+    do_run_qemu(&run.arch, &elf, &bin, &disk, debug, run);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_source_path_resolves_relative_paths_against_root() {
+        let root = Path::new("/project/root");
+        assert_eq!(
+            manifest_source_path(root, Path::new("assets/origin.bin")),
+            root.join("assets/origin.bin")
+        );
+    }
+
+    #[test]
+    fn manifest_source_path_leaves_absolute_paths_unchanged() {
+        let root = Path::new("/project/root");
+        let absolute = Path::new("/elsewhere/origin.bin");
+        assert_eq!(manifest_source_path(root, absolute), absolute);
+    }
 
-            do_run_qemu(arch, &elf, &bin, &disk);
+    #[test]
+    fn create_dir_recursive_creates_nested_dirs_and_is_idempotent() {
+        let mut disk = std::io::Cursor::new(vec![0u8; 4 * 1024 * 1024]);
+        fatfs::format_volume(&mut disk, fatfs::FormatVolumeOptions::new()).unwrap();
+        let fs = fatfs::FileSystem::new(&mut disk, fatfs::FsOptions::new()).unwrap();
+        let root_dir = fs.root_dir();
+
+        create_dir_recursive(&root_dir, "etc/app/config");
+        // A second call sharing a prefix must not error on AlreadyExists.
+        create_dir_recursive(&root_dir, "etc/app/logs");
+
+        assert!(root_dir.open_dir("etc").is_ok());
+        assert!(root_dir.open_dir("etc/app").is_ok());
+        assert!(root_dir.open_dir("etc/app/config").is_ok());
+        assert!(root_dir.open_dir("etc/app/logs").is_ok());
+    }
+
+    #[test]
+    fn partition_layout_unpartitioned_uses_whole_disk() {
+        let (offset, len) = partition_layout(64 * 1024 * 1024, false).unwrap();
+        assert_eq!(offset, 0);
+        assert_eq!(len, 64 * 1024 * 1024);
+    }
+
+    #[test]
+    fn partition_layout_partitioned_reserves_mbr_region() {
+        let disk_size = 64 * 1024 * 1024;
+        let (offset, len) = partition_layout(disk_size, true).unwrap();
+        assert_eq!(offset, MBR_RESERVED_SECTORS * SECTOR_SIZE);
+        assert_eq!(len, disk_size - offset);
+    }
+
+    #[test]
+    fn partition_layout_rejects_disk_too_small_for_mbr_reservation() {
+        let reservation = MBR_RESERVED_SECTORS * SECTOR_SIZE;
+        assert!(partition_layout(reservation, true).is_err());
+        assert!(partition_layout(reservation - 1, true).is_err());
+        assert!(partition_layout(reservation + 1, true).is_ok());
+    }
+
+    fn temp_test_path(name: &str) -> PathBuf {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("xtask-test-{}-{}-{}", process::id(), name, n))
+    }
+
+    #[test]
+    fn disk_format_name_round_trips_through_disk_format_from_name() {
+        for fmt in [DiskFormat::Fat32, DiskFormat::Ext2] {
+            assert_eq!(disk_format_from_name(disk_format_name(fmt)), Some(fmt));
+        }
+        assert_eq!(disk_format_from_name("not-a-format"), None);
+    }
+
+    #[test]
+    fn disk_image_up_to_date_false_when_no_marker_was_written() {
+        let root = temp_test_path("no-marker-root");
+        std::fs::create_dir_all(&root).unwrap();
+        let disk = root.join("disk.img");
+        std::fs::write(&disk, b"fake image").unwrap();
+
+        assert!(!disk_image_up_to_date(&disk, &root, DiskFormat::Fat32, false));
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn disk_image_up_to_date_detects_format_and_partition_changes() {
+        let root = temp_test_path("fmt-change-root");
+        std::fs::create_dir_all(&root).unwrap();
+        let disk = root.join("disk.img");
+        std::fs::write(&disk, b"fake image").unwrap();
+        write_disk_image_marker(&disk, DiskFormat::Fat32, false);
+
+        assert!(disk_image_up_to_date(&disk, &root, DiskFormat::Fat32, false));
+        // Same mtimes, but a different --fs: must not reuse the stale image.
+        assert!(!disk_image_up_to_date(&disk, &root, DiskFormat::Ext2, false));
+        // Same mtimes and format, but --partitioned toggled: also stale.
+        assert!(!disk_image_up_to_date(&disk, &root, DiskFormat::Fat32, true));
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn offset_io_confines_reads_and_writes_to_its_window() {
+        let path = temp_test_path("offset-io");
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(32).unwrap();
+
+        {
+            let mut io = OffsetIo::new(&file, 8, 16);
+            io.write_all(&[0xAB; 16]).unwrap();
         }
+
+        let mut raw = vec![0u8; 32];
+        (&file).seek(std::io::SeekFrom::Start(0)).unwrap();
+        (&file).read_exact(&mut raw).unwrap();
+
+        assert_eq!(&raw[0..8], &[0u8; 8][..]);
+        assert_eq!(&raw[8..24], &[0xAB; 16][..]);
+        assert_eq!(&raw[24..32], &[0u8; 8][..]);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn offset_io_rejects_seek_before_start_of_window() {
+        let path = temp_test_path("offset-io-seek");
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(16).unwrap();
+
+        let mut io = OffsetIo::new(&file, 4, 8);
+        assert!(io.seek(std::io::SeekFrom::Current(-1)).is_err());
+
+        std::fs::remove_file(&path).ok();
     }
 }